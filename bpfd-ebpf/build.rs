@@ -0,0 +1,190 @@
+//! Compiles the C dispatcher probes alongside the Rust eBPF programs.
+//!
+//! This is gated behind `BPFD_BUILD_EBPF` so a plain `cargo build`/`cargo
+//! check` (and rust-analyzer) doesn't require a clang + libbpf toolchain;
+//! `cargo xtask build-ebpf` sets the env var to opt in.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use cargo_metadata::MetadataCommand;
+
+/// Run `cmd`, logging it first, and panic with an error that distinguishes
+/// exiting with a code from being killed by a signal, with the full command
+/// included for context. A build script has no caller to hand a `Result`
+/// to, so a diagnostic panic is the best we can do here.
+fn run(cmd: &mut Command) {
+    eprintln!("running {cmd:?}");
+    let status = cmd
+        .status()
+        .unwrap_or_else(|e| panic!("failed to run {cmd:?}: {e}"));
+    match status.code() {
+        Some(0) => {}
+        Some(code) => panic!("{cmd:?} exited with code {code}"),
+        None => panic!("{cmd:?} terminated by signal"),
+    }
+}
+
+/// The C dispatcher probes we compile, as explicit `(source, object)` pairs
+/// relative to `src/bpf` and `OUT_DIR` respectively. Keeping this list
+/// explicit, rather than globbing the directory, means a probe that fails
+/// to compile (or a listed source file that does not exist) fails the build
+/// loudly instead of silently missing from the output. A source file in
+/// `src/bpf` that is not listed here is not compiled.
+const C_BPF_PROBES: &[(&str, &str)] = &[
+    ("xdp_dispatcher.bpf.c", "xdp_dispatcher.bpf.o"),
+    ("tc_dispatcher.bpf.c", "tc_dispatcher.bpf.o"),
+];
+
+const PROBES_MODULE: &str = "c_bpf_probes.rs";
+
+/// Where the libbpf headers needed to compile the C dispatcher come from.
+enum HeaderSource {
+    /// Extract headers from an existing libbpf source checkout via `make
+    /// install_headers`.
+    Checkout(PathBuf),
+    /// Extract the headers vendored in the `libbpf-sys` crate.
+    Vendored,
+}
+
+fn header_source(workspace_root: &Path) -> HeaderSource {
+    if let Some(dir) = env::var_os("BPFD_LIBBPF_DIR") {
+        return HeaderSource::Checkout(PathBuf::from(dir));
+    }
+    let submodule = workspace_root.join("libbpf");
+    if submodule.join("src").is_dir() {
+        return HeaderSource::Checkout(submodule);
+    }
+    HeaderSource::Vendored
+}
+
+fn get_libbpf_headers(source: HeaderSource, include_path: &Path) {
+    fs::create_dir_all(include_path).expect("failed to create include dir");
+    match source {
+        HeaderSource::Checkout(libbpf_dir) => {
+            let mut includedir = OsString::from("INCLUDEDIR=");
+            includedir.push(include_path.as_os_str());
+            run(Command::new("make")
+                .current_dir(libbpf_dir.join("src"))
+                .arg(includedir)
+                .arg("install_headers"));
+        }
+        HeaderSource::Vendored => {
+            let bpf_dir = include_path.join("bpf");
+            fs::create_dir_all(&bpf_dir).expect("failed to create include/bpf dir");
+            for (name, contents) in libbpf_sys::API_HEADERS.iter() {
+                fs::write(bpf_dir.join(name), contents).expect("failed to write vendored header");
+            }
+        }
+    }
+}
+
+/// Map cargo's notion of the target's endianness onto the `bpfel`/`bpfeb`
+/// clang target triples, rather than relying on a `--target` flag: a build
+/// script only ever runs for the crate's actual build target, so
+/// `CARGO_CFG_TARGET_ENDIAN` is the authoritative source.
+fn bpf_clang_target() -> &'static str {
+    match env::var("CARGO_CFG_TARGET_ENDIAN").as_deref() {
+        Ok("big") => "bpfeb",
+        Ok("little") => "bpfel",
+        other => panic!("unexpected CARGO_CFG_TARGET_ENDIAN: {other:?}"),
+    }
+}
+
+fn compile_with_clang(src: &Path, out: &Path, include_path: &Path, bpf_target: &str) {
+    let clang = env::var_os("CLANG").unwrap_or_else(|| OsStr::new("/usr/bin/clang").to_owned());
+    let arch = match env::consts::ARCH {
+        "x86_64" => "x86",
+        "aarch64" => "arm64",
+        other => other,
+    };
+    let mut include_arg = OsString::from("-I");
+    include_arg.push(include_path.as_os_str());
+    let output = Command::new(clang)
+        .arg(include_arg)
+        .arg("-g")
+        .arg("-O2")
+        .arg("-target")
+        .arg(bpf_target)
+        .arg("-c")
+        .arg(format!("-D__TARGET_ARCH_{arch}"))
+        .arg(src)
+        .arg("-o")
+        .arg(out)
+        .output()
+        .expect("failed to execute clang");
+    if !output.status.success() {
+        panic!(
+            "failed to compile {src:?}\nstdout=\n{}\nstderr=\n{}\n",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+}
+
+fn probe_const_name(object: &str) -> String {
+    object
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn write_probes_module(out_dir: &Path) {
+    let mut module = String::new();
+    for (_, object) in C_BPF_PROBES {
+        let const_name = probe_const_name(object);
+        let path = out_dir.join(object);
+        module.push_str(&format!(
+            "pub const {const_name}: &str = {path:?};\n",
+            path = path.display()
+        ));
+    }
+    fs::write(out_dir.join(PROBES_MODULE), module).expect("failed to write probes module");
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=BPFD_BUILD_EBPF");
+    println!("cargo:rerun-if-env-changed=BPFD_LIBBPF_DIR");
+    println!("cargo:rerun-if-env-changed=CLANG");
+
+    if env::var_os("BPFD_BUILD_EBPF").is_none() {
+        return;
+    }
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
+    let metadata = MetadataCommand::new()
+        .manifest_path(Path::new(&manifest_dir).join("Cargo.toml"))
+        .no_deps()
+        .exec()
+        .expect("unable to run cargo metadata");
+    let workspace_root = metadata.workspace_root.into_std_path_buf();
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    let include_path = out_dir.join("include");
+    get_libbpf_headers(header_source(&workspace_root), &include_path);
+
+    let src_dir = workspace_root.join("bpfd-ebpf/src/bpf");
+    let bpf_target = bpf_clang_target();
+    for (source, object) in C_BPF_PROBES {
+        let src = src_dir.join(source);
+        println!("cargo:rerun-if-changed={}", src.display());
+        if !src.exists() {
+            panic!("C dispatcher probe {src:?} listed in C_BPF_PROBES does not exist");
+        }
+        let out = out_dir.join(object);
+        compile_with_clang(&src, &out, &include_path, bpf_target);
+    }
+
+    write_probes_module(&out_dir);
+}
@@ -1,14 +1,8 @@
-use std::{
-    env, fs,
-    path::{Path, PathBuf},
-    process::Command,
-    string::String,
-};
+use std::{path::PathBuf, process::Command, string::String};
 
 use anyhow::{bail, Context, Result};
+use cargo_metadata::MetadataCommand;
 use clap::Parser;
-use lazy_static::lazy_static;
-use serde_json::Value;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Architecture {
@@ -48,34 +42,46 @@ pub struct Options {
     /// Compile rust ebpf dispatcher
     #[clap(long)]
     pub compile_rust_ebpf: bool,
-    /// Libbpf dir, required for compiling C code
+    /// Libbpf dir used to build the C dispatcher. If omitted, the `libbpf`
+    /// git submodule at the workspace root is used when checked out,
+    /// falling back to the headers vendored in the libbpf-sys crate.
     #[clap(long, action)]
-    pub libbpf_dir: PathBuf,
+    pub libbpf_dir: Option<PathBuf>,
 }
 
-lazy_static! {
-    pub static ref WORKSPACE_ROOT: String = workspace_root();
+/// Run `cmd`, logging it first, and turn a non-zero exit into an
+/// [`anyhow::Error`] that distinguishes exiting with a code from being
+/// killed by a signal, with the full command included for context.
+pub(crate) fn run(cmd: &mut Command) -> Result<()> {
+    eprintln!("running {cmd:?}");
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run {cmd:?}"))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => bail!("{cmd:?} exited with code {code}"),
+        None => bail!("{cmd:?} terminated by signal"),
+    }
 }
 
-fn workspace_root() -> String {
-    let output = Command::new("cargo").arg("metadata").output().unwrap();
-    if !output.status.success() {
-        panic!("unable to run cargo metadata")
-    }
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let v: Value = serde_json::from_str(&stdout).unwrap();
-    v["workspace_root"].as_str().unwrap().to_string()
+pub fn workspace_root() -> Result<String> {
+    let metadata = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .context("failed to run `cargo metadata`")?;
+    Ok(metadata.workspace_root.into_string())
 }
 
 pub fn build_ebpf(opts: Options) -> anyhow::Result<()> {
+    let workspace_root = workspace_root()?;
     if opts.compile_rust_ebpf {
-        build_rust_ebpf(&opts)?;
+        build_rust_ebpf(&opts, &workspace_root)?;
     }
-    build_c_ebpf(&opts)
+    build_c_ebpf(&opts, &workspace_root)
 }
 
-fn build_rust_ebpf(opts: &Options) -> anyhow::Result<()> {
-    let mut dir = PathBuf::from(WORKSPACE_ROOT.to_string());
+fn build_rust_ebpf(opts: &Options, workspace_root: &str) -> anyhow::Result<()> {
+    let mut dir = PathBuf::from(workspace_root);
     dir.push("bpfd-ebpf");
 
     let target = format!("--target={}", opts.target);
@@ -88,93 +94,40 @@ fn build_rust_ebpf(opts: &Options) -> anyhow::Result<()> {
         "-Z",
         "build-std=core",
     ];
-    let status = Command::new("cargo")
-        .current_dir(&dir)
-        .args(args)
-        .status()
-        .expect("failed to build bpf program");
-    assert!(status.success());
-    Ok(())
-}
-
-fn get_libbpf_headers<P: AsRef<Path>>(libbpf_dir: P, include_path: P) -> anyhow::Result<()> {
-    let dir = include_path.as_ref();
-    fs::create_dir_all(dir)?;
-    let status = Command::new("make")
-        .current_dir(libbpf_dir.as_ref().join("src"))
-        .arg(format!("INCLUDEDIR={}", dir.as_os_str().to_string_lossy()))
-        .arg("install_headers")
-        .status()
-        .expect("failed to build get libbpf headers");
-    assert!(status.success());
-    Ok(())
+    run(Command::new("cargo").current_dir(&dir).args(args))
 }
 
-fn build_c_ebpf(opts: &Options) -> anyhow::Result<()> {
-    let mut src = PathBuf::from(WORKSPACE_ROOT.to_string());
-    src.push("bpfd-ebpf/src/bpf");
-
-    let mut out_path = PathBuf::from(WORKSPACE_ROOT.to_string());
-    out_path.push("target");
-    out_path.push(opts.target.to_string());
-    out_path.push("release");
-
-    let include_path = out_path.join("include");
-    get_libbpf_headers(&opts.libbpf_dir, &include_path)?;
-    let files = fs::read_dir(&src).unwrap();
-    for file in files {
-        let p = file.unwrap().path();
-        if let Some(ext) = p.extension() {
-            if ext == "c" {
-                let mut out = PathBuf::from(&out_path);
-                out.push(p.file_name().unwrap());
-                out.set_extension("o");
-                compile_with_clang(&p, &out, &include_path)?;
-            }
-        }
+/// The C dispatcher is now compiled by `bpfd-ebpf`'s own `build.rs`, gated
+/// behind the `BPFD_BUILD_EBPF` env var. Trigger it with a normal `cargo
+/// build`, forwarding the options this xtask command was given, so
+/// `cargo build` alone (without `BPFD_BUILD_EBPF` set) stays cheap and
+/// doesn't require a working clang/libbpf toolchain.
+///
+/// `--target` must be forwarded here too, not just in `build_rust_ebpf`:
+/// `bpfd-ebpf/build.rs` derives the clang `-target bpfel|bpfeb` from
+/// `CARGO_CFG_TARGET_ENDIAN`, which cargo only sets correctly for the build
+/// script if this invocation is actually building `bpfd-ebpf` for that
+/// target, same as the Rust side's nightly/build-std requirements.
+fn build_c_ebpf(opts: &Options, workspace_root: &str) -> anyhow::Result<()> {
+    let target = format!("--target={}", opts.target);
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(workspace_root)
+        .env("BPFD_BUILD_EBPF", "1")
+        .args([
+            "+nightly",
+            "build",
+            "--verbose",
+            "-p",
+            "bpfd-ebpf",
+            target.as_str(),
+            "-Z",
+            "build-std=core",
+        ]);
+    if let Some(dir) = &opts.libbpf_dir {
+        cmd.env("BPFD_LIBBPF_DIR", dir);
     }
-    Ok(())
-}
-
-/// Build eBPF programs with clang and libbpf headers.
-fn compile_with_clang<P: Clone + AsRef<Path>>(
-    src: P,
-    out: P,
-    include_path: P,
-) -> anyhow::Result<()> {
-    let clang = match env::var("CLANG") {
-        Ok(val) => val,
-        Err(_) => String::from("/usr/bin/clang"),
-    };
-    let arch = match std::env::consts::ARCH {
-        "x86_64" => "x86",
-        "aarch64" => "arm64",
-        _ => std::env::consts::ARCH,
-    };
-    let mut cmd = Command::new(clang);
-    cmd.arg(format!("-I{}", include_path.as_ref().to_string_lossy()))
-        .arg("-g")
-        .arg("-O2")
-        .arg("-target")
-        .arg("bpf")
-        .arg("-c")
-        .arg(format!("-D__TARGET_ARCH_{arch}"))
-        .arg(src.as_ref().as_os_str())
-        .arg("-o")
-        .arg(out.as_ref().as_os_str());
-
-    let output = cmd.output().context("Failed to execute clang")?;
-    if !output.status.success() {
-        bail!(
-            "Failed to compile eBPF programs\n \
-            stdout=\n \
-            {}\n \
-            stderr=\n \
-            {}\n",
-            String::from_utf8(output.stdout).unwrap(),
-            String::from_utf8(output.stderr).unwrap()
-        );
+    if opts.release {
+        cmd.arg("--release");
     }
-
-    Ok(())
+    run(&mut cmd)
 }
@@ -0,0 +1,138 @@
+//! Keep the `libbpf` git submodule reproducible across machines and CI.
+//!
+//! The submodule SHA we expect to be checked out is recorded in
+//! `xtask/libbpf.sha`. `cargo xtask libbpf-sync` refreshes that file from the
+//! submodule's current commit; `cargo xtask libbpf-sync --check` verifies the
+//! checked-out submodule still matches what's recorded, which is what CI
+//! runs so a stale or un-updated submodule fails loudly instead of silently
+//! building against the wrong headers.
+
+use std::{fs, path::PathBuf, process::Command};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+
+use crate::build_ebpf::{self};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Verify the submodule matches the recorded SHA instead of recording it
+    #[clap(long)]
+    pub check: bool,
+}
+
+fn sha_file(workspace_root: &str) -> PathBuf {
+    PathBuf::from(workspace_root)
+        .join("xtask")
+        .join("libbpf.sha")
+}
+
+/// Parse a single line of `git submodule status` output, which is a status
+/// prefix character followed by the SHA and path. Only `' '` (in sync) and
+/// `'+'` (checked out SHA differs from the index, e.g. local edits) carry a
+/// SHA that actually reflects what's on disk. `'-'` (not initialized) and
+/// `'U'` (gitlink has a merge conflict) report the SHA recorded in the
+/// index instead, so trusting it here would silently "verify" against a
+/// submodule that was never actually built against.
+fn parse_submodule_status_line(line: &str) -> Result<String> {
+    let mut chars = line.chars();
+    let prefix = chars.next().context("empty `git submodule status` line")?;
+    let rest = chars.as_str();
+    match prefix {
+        ' ' | '+' => {}
+        '-' => bail!(
+            "libbpf submodule is not initialized (run `git submodule update \
+             --init libbpf`); `git submodule status` reports the SHA recorded \
+             in the index, not what's checked out, so it can't be trusted here"
+        ),
+        'U' => bail!(
+            "libbpf submodule has a merge conflict; resolve it and run `git \
+             submodule update --init libbpf`; `git submodule status` reports \
+             the SHA recorded in the index, not what's checked out, so it \
+             can't be trusted here"
+        ),
+        other => bail!("unexpected `git submodule status` prefix {other:?} in line {line:?}"),
+    }
+    let sha = rest
+        .split_whitespace()
+        .next()
+        .context("unable to parse `git submodule status` output")?;
+    Ok(sha.to_string())
+}
+
+fn current_submodule_sha(workspace_root: &str) -> Result<String> {
+    let output = Command::new("git")
+        .current_dir(workspace_root)
+        .args(["submodule", "status", "libbpf"])
+        .output()
+        .context("failed to run git submodule status")?;
+    if !output.status.success() {
+        bail!("git submodule status failed");
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let line = stdout
+        .lines()
+        .next()
+        .context("`git submodule status` produced no output")?;
+    parse_submodule_status_line(line)
+}
+
+pub fn libbpf_sync(opts: Options) -> Result<()> {
+    let workspace_root = build_ebpf::workspace_root()?;
+    let current = current_submodule_sha(&workspace_root)?;
+    let path = sha_file(&workspace_root);
+
+    if opts.check {
+        let recorded = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if recorded.trim() != current {
+            bail!(
+                "libbpf submodule is at {current} but {} records {}; run \
+                 `cargo xtask libbpf-sync` and commit the update",
+                path.display(),
+                recorded.trim(),
+            );
+        }
+    } else {
+        fs::write(&path, format!("{current}\n"))
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_submodule_status_line;
+
+    #[test]
+    fn in_sync_is_accepted() {
+        let sha = parse_submodule_status_line(
+            " 1234567890123456789012345678901234567890 libbpf (heads/main)",
+        )
+        .unwrap();
+        assert_eq!(sha, "1234567890123456789012345678901234567890");
+    }
+
+    #[test]
+    fn checked_out_sha_differs_from_index_is_accepted() {
+        let sha = parse_submodule_status_line(
+            "+1234567890123456789012345678901234567890 libbpf (heads/main)",
+        )
+        .unwrap();
+        assert_eq!(sha, "1234567890123456789012345678901234567890");
+    }
+
+    #[test]
+    fn uninitialized_is_rejected() {
+        let err = parse_submodule_status_line("-1234567890123456789012345678901234567890 libbpf")
+            .unwrap_err();
+        assert!(err.to_string().contains("not initialized"));
+    }
+
+    #[test]
+    fn merge_conflict_is_rejected() {
+        let err = parse_submodule_status_line("U1234567890123456789012345678901234567890 libbpf")
+            .unwrap_err();
+        assert!(err.to_string().contains("merge conflict"));
+    }
+}